@@ -0,0 +1,209 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! A jobserver-style scheduler that caps how many `commit_project` jobs
+//! may run concurrently, instead of handing every ready job straight to
+//! `tokio::spawn` and letting the machine decide. The build phase
+//! (CPU-bound) is not run through this scheduler: `Compiler` caps it with a
+//! rayon thread pool of `Config::build_tokens` threads, while the commit
+//! phase (IO-bound, tokio) gets a `TokenPool` of `Config::commit_tokens`,
+//! since their ideal widths differ. The two phases run one after the
+//! other rather than overlapping, so tokens aren't shared or handed off
+//! between them -- only the *pool sizes* are independent.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+
+/// A cheap, cloneable flag shared between the build and commit phases so
+/// that in `fail_fast` mode, the first `BuildProjectError` anywhere can
+/// abort every other in-flight and queued project without plumbing a
+/// `Result` back through rayon's `par_iter` or the scheduler's job futures.
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self(Arc::new(AtomicBool::new(false)))
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// A single pool of `capacity` tokens. Acquiring a token blocks (without
+/// spinning) until one is free; dropping the guard returns it immediately.
+pub struct TokenPool {
+    semaphore: Arc<Semaphore>,
+}
+
+impl TokenPool {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(capacity.max(1))),
+        }
+    }
+
+    /// Number of tokens, defaulting to the available parallelism of the
+    /// machine when the caller doesn't override it via `Config`.
+    pub fn default_capacity() -> usize {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    }
+
+    pub async fn acquire(&self) -> TokenGuard {
+        let permit = Arc::clone(&self.semaphore)
+            .acquire_owned()
+            .await
+            .expect("TokenPool semaphore is never closed");
+        TokenGuard { _permit: permit }
+    }
+}
+
+/// Held for the duration of a single scheduled job; returns its token to the
+/// pool on drop so the next queued job can be dispatched.
+pub struct TokenGuard {
+    _permit: tokio::sync::OwnedSemaphorePermit,
+}
+
+/// Runs a queue of jobs with at most one job per pool token running at once,
+/// draining every queued job (including ones queued after the scheduler
+/// started) before `run` returns, even if earlier jobs failed.
+///
+/// `jobs` is a queue rather than a fixed `Vec` so that callers modeling a
+/// two-phase pipeline (e.g. build handing off to commit) can push follow-up
+/// work onto a shared queue as earlier jobs complete.
+pub struct Scheduler<T> {
+    pool: Arc<TokenPool>,
+    queue: Arc<Mutex<VecDeque<Job<T>>>>,
+}
+
+type Job<T> = std::pin::Pin<Box<dyn Future<Output = T> + Send>>;
+
+impl<T: Send + 'static> Scheduler<T> {
+    pub fn new(pool: Arc<TokenPool>) -> Self {
+        Self {
+            pool,
+            queue: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// Queues a job. Safe to call while `run` is executing, for schedulers
+    /// that feed one phase's completions into another phase's queue.
+    pub fn push(&self, job: impl Future<Output = T> + Send + 'static) {
+        self.queue.lock().unwrap().push_back(Box::pin(job));
+    }
+
+    /// Drains the queue, running as many jobs concurrently as the pool has tokens,
+    /// and returns every job's result in the order the jobs completed.
+    pub async fn run(&self) -> Vec<T> {
+        self.run_cancellable(None).await
+    }
+
+    /// Like `run`, but stops dispatching queued jobs as soon as `cancel` is
+    /// observed cancelled, dropping them instead of running them. Jobs
+    /// already in flight are still awaited to completion, since there's no
+    /// way to abort a future mid-poll without cooperation from the job
+    /// itself.
+    pub async fn run_until_cancelled(&self, cancel: &CancellationToken) -> Vec<T> {
+        self.run_cancellable(Some(cancel)).await
+    }
+
+    async fn run_cancellable(&self, cancel: Option<&CancellationToken>) -> Vec<T> {
+        let (done_tx, mut done_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut in_flight = 0usize;
+        let mut results = Vec::new();
+
+        loop {
+            if cancel.map_or(false, CancellationToken::is_cancelled) {
+                self.queue.lock().unwrap().clear();
+            }
+
+            let has_queued = !self.queue.lock().unwrap().is_empty();
+            if !has_queued && in_flight == 0 {
+                break;
+            }
+
+            if has_queued {
+                // Only take a job off the queue once a token is actually
+                // free, not up front: a job popped here is dispatched no
+                // matter what happens later, so popping the whole queue
+                // immediately (each job then merely parking on `acquire`)
+                // would leave cancellation nothing to clear. Racing the
+                // acquire against `done_rx` keeps already-running jobs'
+                // results flowing in while we wait for capacity.
+                tokio::select! {
+                    token = self.pool.acquire() => {
+                        if cancel.map_or(false, CancellationToken::is_cancelled) {
+                            drop(token);
+                            self.queue.lock().unwrap().clear();
+                        } else if let Some(job) = self.queue.lock().unwrap().pop_front() {
+                            let done_tx = done_tx.clone();
+                            in_flight += 1;
+                            tokio::spawn(async move {
+                                let result = job.await;
+                                drop(token);
+                                // Ignore send errors: the receiver only goes
+                                // away once `run` has already seen every job
+                                // finish.
+                                let _ = done_tx.send(result);
+                            });
+                        }
+                    }
+                    Some(result) = done_rx.recv(), if in_flight > 0 => {
+                        results.push(result);
+                        in_flight -= 1;
+                    }
+                }
+            } else if let Some(result) = done_rx.recv().await {
+                results.push(result);
+                in_flight -= 1;
+            }
+        }
+
+        results
+    }
+}
+
+/// A human-readable account of how a `fail_fast`-aware build run ended, so
+/// the caller can tell "finished cleanly" apart from "gave up early" without
+/// inspecting error counts.
+#[derive(Debug)]
+pub enum BuildStatus {
+    /// Every pending project ran to completion (some may still have failed).
+    Completed { project_count: usize, error_count: usize },
+    /// `fail_fast` aborted the remaining projects after the first error.
+    FailedFast { completed: usize, total: usize },
+}
+
+impl std::fmt::Display for BuildStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildStatus::Completed {
+                project_count,
+                error_count,
+            } => write!(
+                f,
+                "completed {} project(s) with {} error(s)",
+                project_count, error_count
+            ),
+            BuildStatus::FailedFast { completed, total } => write!(
+                f,
+                "failed fast after {} of {} project(s)",
+                completed, total
+            ),
+        }
+    }
+}
@@ -6,10 +6,13 @@
  */
 
 use crate::build_project::{build_project, build_schema, check_project, commit_project};
+use crate::build_scheduler::{BuildStatus, CancellationToken, Scheduler, TokenPool};
 use crate::compiler_state::{CompilerState, ProjectName};
 use crate::config::Config;
+use crate::diagnostics::{self, DiagnosticFormat};
 use crate::errors::{BuildProjectError, Error, Result};
 use crate::graphql_asts::GraphQLAsts;
+use crate::lsp::{self, ClientMessage};
 use crate::{
     artifact_map::ArtifactMap,
     watchman::{source_for_location, FileSource},
@@ -17,11 +20,17 @@ use crate::{
 use common::{PerfLogEvent, PerfLogger};
 use graphql_ir::ValidationError;
 use log::{error, info};
+use lsp_server::Connection;
+use lsp_types::Url;
 use rayon::prelude::*;
 use schema::Schema;
 use std::fmt::Write;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
 use tokio::task;
+use tracing::Instrument;
 
 pub struct Compiler<TPerfLogger>
 where
@@ -29,16 +38,38 @@ where
 {
     config: Arc<Config>,
     perf_logger: Arc<TPerfLogger>,
+    // Where `DiagnosticFormat::Json` errors get written. Defaults to stdout;
+    // override with `with_diagnostic_sink` (e.g. in tests, or to route
+    // diagnostics to a file/socket instead of the process's own stdout).
+    diagnostic_sink: Arc<Mutex<dyn std::io::Write + Send>>,
+    // Built once, sized from the config in effect at construction time, and
+    // reused for every build (including every incremental rebuild in watch
+    // mode) rather than paying thread-spawn cost on each one.
+    build_pool: Arc<rayon::ThreadPool>,
 }
 
 impl<TPerfLogger: PerfLogger> Compiler<TPerfLogger> {
     pub fn new(config: Config, perf_logger: Arc<TPerfLogger>) -> Self {
+        let build_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(config.build_tokens)
+            .build()
+            .expect("failed to build the project build thread pool");
         Self {
             config: Arc::new(config),
             perf_logger,
+            diagnostic_sink: Arc::new(Mutex::new(std::io::stdout())),
+            build_pool: Arc::new(build_pool),
         }
     }
 
+    /// Overrides where JSON diagnostics (`DiagnosticFormat::Json`) are
+    /// written, instead of the default of stdout.
+    pub fn with_diagnostic_sink(mut self, sink: impl std::io::Write + Send + 'static) -> Self {
+        self.diagnostic_sink = Arc::new(Mutex::new(sink));
+        self
+    }
+
+    #[tracing::instrument(name = "compiler_setup", skip_all)]
     pub async fn compile(&self) -> Result<CompilerState> {
         let setup_event = self.perf_logger.create_event("compiler_setup");
 
@@ -54,6 +85,7 @@ impl<TPerfLogger: PerfLogger> Compiler<TPerfLogger> {
         Ok(compiler_state)
     }
 
+    #[tracing::instrument(name = "build_schemas", skip_all)]
     pub fn build_schemas(
         &self,
         compiler_state: &CompilerState,
@@ -69,6 +101,7 @@ impl<TPerfLogger: PerfLogger> Compiler<TPerfLogger> {
         schemas
     }
 
+    #[tracing::instrument(name = "watch_with_callback", skip_all)]
     pub async fn watch_with_callback<F>(&self, mut callback: F) -> Result<()>
     where
         F: FnMut(Result<()>),
@@ -126,6 +159,148 @@ impl<TPerfLogger: PerfLogger> Compiler<TPerfLogger> {
         }
     }
 
+    /// Runs the compiler as a persistent Language Server, publishing
+    /// diagnostics over `connection` as projects are checked instead of
+    /// logging them. Built directly on the same `FileSource`/subscription
+    /// infrastructure as `watch_with_callback`, racing the Watchman
+    /// subscription against incoming client messages in a `select!`. A
+    /// document edit re-checks against whatever is already on disk, the
+    /// same as `relay/forceRebuild` -- `CompilerState` has no notion of an
+    /// edit that hasn't landed on disk yet, so there's no live-as-you-type
+    /// checking of unsaved changes.
+    #[tracing::instrument(name = "watch_with_lsp", skip_all)]
+    pub async fn watch_with_lsp(&self, connection: &Connection) -> Result<()> {
+        let setup_event = self.perf_logger.create_event("compiler_setup");
+
+        let file_source = FileSource::connect(&self.config, &setup_event).await?;
+        let (mut compiler_state, mut subscription) = file_source
+            .subscribe(&setup_event, self.perf_logger.as_ref())
+            .await?;
+        let schemas = self.build_schemas(&compiler_state, &setup_event);
+
+        lsp::initialize(connection)?;
+
+        // Tracks which files currently have diagnostics published, so a file
+        // that goes clean on a later check gets an explicit empty
+        // `publishDiagnostics` instead of keeping stale squiggles forever.
+        let mut published_files: HashSet<Url> = HashSet::new();
+
+        self.publish_check_result(
+            connection,
+            &mut published_files,
+            self.check_projects(&mut compiler_state, &schemas, &setup_event)
+                .await,
+        )?;
+
+        let mut client_messages = ClientMessageReceiver::spawn(connection);
+
+        loop {
+            tokio::select! {
+                file_source_changes = subscription.next_change() => {
+                    if let Some(file_source_changes) = file_source_changes? {
+                        let incremental_check_event =
+                            self.perf_logger.create_event("incremental_check_event");
+
+                        let had_new_changes = compiler_state.merge_file_source_changes(
+                            &self.config,
+                            &file_source_changes,
+                            &incremental_check_event,
+                            self.perf_logger.as_ref(),
+                        )?;
+                        if had_new_changes {
+                            self.publish_check_result(
+                                connection,
+                                &mut published_files,
+                                self.check_projects(&mut compiler_state, &schemas, &incremental_check_event)
+                                    .await,
+                            )?;
+                        }
+                        self.perf_logger.complete_event(incremental_check_event);
+                        self.perf_logger.flush();
+                    }
+                }
+                message = client_messages.recv() => {
+                    match message? {
+                        // `CompilerState` has no way to apply an edit that
+                        // hasn't been written to disk yet, so a document
+                        // edit can only re-check against what's already on
+                        // disk -- the same thing `ForceRebuild` does. This
+                        // falls short of live-as-you-type for unsaved
+                        // changes, but still gets diagnostics published
+                        // without waiting for the next Watchman event.
+                        Some(ClientMessage::DocumentEdit(_)) => {
+                            let edit_check_event =
+                                self.perf_logger.create_event("lsp_document_edit_check_event");
+                            self.publish_check_result(
+                                connection,
+                                &mut published_files,
+                                self.check_projects(&mut compiler_state, &schemas, &edit_check_event).await,
+                            )?;
+                            self.perf_logger.complete_event(edit_check_event);
+                        }
+                        Some(ClientMessage::ForceRebuild) => {
+                            let force_rebuild_event =
+                                self.perf_logger.create_event("lsp_force_rebuild_event");
+                            self.publish_check_result(
+                                connection,
+                                &mut published_files,
+                                self.check_projects(&mut compiler_state, &schemas, &force_rebuild_event).await,
+                            )?;
+                            self.perf_logger.complete_event(force_rebuild_event);
+                        }
+                        Some(ClientMessage::Shutdown) | None => break,
+                    }
+                }
+            }
+        }
+
+        self.perf_logger.complete_event(setup_event);
+
+        Ok(())
+    }
+
+    fn publish_check_result(
+        &self,
+        connection: &Connection,
+        published_files: &mut HashSet<Url>,
+        result: Result<()>,
+    ) -> Result<()> {
+        let mut diagnostics_by_file = HashMap::new();
+        match result {
+            Ok(()) => {}
+            Err(Error::BuildProjectsErrors { errors }) => {
+                for error in &errors {
+                    if let BuildProjectError::ValidationErrors { errors } = error {
+                        for validation_error in errors {
+                            lsp::diagnostics_for_validation_error(
+                                &self.config.root_dir,
+                                validation_error,
+                                &mut diagnostics_by_file,
+                            );
+                        }
+                    }
+                }
+            }
+            Err(other) => return Err(other),
+        }
+        // Any file that had diagnostics last round but isn't in this round's
+        // map is now clean; publish an empty array to clear it client-side.
+        for file in published_files.iter() {
+            diagnostics_by_file.entry(file.clone()).or_insert_with(Vec::new);
+        }
+        *published_files = diagnostics_by_file
+            .iter()
+            .filter(|(_, diagnostics)| !diagnostics.is_empty())
+            .map(|(file, _)| file.clone())
+            .collect();
+        lsp::publish_diagnostics(connection, diagnostics_by_file).map_err(|source| {
+            Error::LSPError {
+                detail: source.to_string(),
+            }
+        })
+    }
+
+    #[tracing::instrument(name = "watch", skip_all)]
     pub async fn watch(&self) -> Result<()> {
         let setup_event = self.perf_logger.create_event("compiler_setup");
 
@@ -152,6 +327,7 @@ impl<TPerfLogger: PerfLogger> Compiler<TPerfLogger> {
                 // 2 watchman change events for the same file
 
                 info!("\n\n[watch-mode] Change detected");
+
                 let had_new_changes = compiler_state.merge_file_source_changes(
                     &self.config,
                     &file_source_changes,
@@ -179,6 +355,7 @@ impl<TPerfLogger: PerfLogger> Compiler<TPerfLogger> {
         }
     }
 
+    #[tracing::instrument(name = "check_projects", skip_all)]
     async fn check_projects(
         &self,
         compiler_state: &mut CompilerState,
@@ -197,13 +374,13 @@ impl<TPerfLogger: PerfLogger> Compiler<TPerfLogger> {
         })?;
 
         let mut build_project_errors = vec![];
+        let config = &self.config;
 
-        match self.config.only_project {
+        match config.only_project {
             Some(project_key) => {
-                let project_config =
-                    self.config.projects.get(&project_key).unwrap_or_else(|| {
-                        panic!("Expected the project {} to exist", &project_key)
-                    });
+                let project_config = config.projects.get(&project_key).unwrap_or_else(|| {
+                    panic!("Expected the project {} to exist", &project_key)
+                });
                 let schema = Arc::clone(schemas.get(&project_config.name).unwrap());
                 check_project(
                     project_config,
@@ -218,7 +395,10 @@ impl<TPerfLogger: PerfLogger> Compiler<TPerfLogger> {
                 .ok();
             }
             None => {
-                for project_config in self.config.projects.values() {
+                for project_config in config.projects.values() {
+                    if config.fail_fast && !build_project_errors.is_empty() {
+                        break;
+                    }
                     if compiler_state.project_has_pending_changes(project_config.name) {
                         let schema = Arc::clone(schemas.get(&project_config.name).unwrap());
                         // TODO: consider running all projects in parallel
@@ -247,14 +427,21 @@ impl<TPerfLogger: PerfLogger> Compiler<TPerfLogger> {
         }
     }
 
+    #[tracing::instrument(name = "build_projects", skip_all)]
     async fn build_projects(
         &self,
         compiler_state: &mut CompilerState,
         setup_event: &impl PerfLogEvent,
     ) -> Result<()> {
+        // Errors are printed as they're discovered, inline in `build_projects`
+        // below, while the project that produced them is still in scope --
+        // by the time they're aggregated into `Error::BuildProjectsErrors`
+        // here, that association is gone.
         let result = build_projects(
             Arc::clone(&self.config),
             Arc::clone(&self.perf_logger),
+            Arc::clone(&self.diagnostic_sink),
+            Arc::clone(&self.build_pool),
             setup_event,
             &compiler_state,
         )
@@ -264,49 +451,128 @@ impl<TPerfLogger: PerfLogger> Compiler<TPerfLogger> {
                 compiler_state.complete_compilation(next_artifacts);
                 Ok(())
             }
-            Err(error) => {
-                if let Error::BuildProjectsErrors { errors } = &error {
-                    for error in errors {
-                        self.print_project_error(error);
-                    }
+            Err(error) => Err(error),
+        }
+    }
+}
+
+fn print_project_error(
+    config: &Config,
+    diagnostic_sink: &Mutex<dyn std::io::Write + Send>,
+    project_name: Option<ProjectName>,
+    error: &BuildProjectError,
+) {
+    match config.diagnostic_format {
+        DiagnosticFormat::Human => print_project_error_human(config, error),
+        DiagnosticFormat::Json => {
+            print_project_error_json(config, diagnostic_sink, project_name, error)
+        }
+    }
+}
+
+fn print_project_error_human(config: &Config, error: &BuildProjectError) {
+    if let BuildProjectError::ValidationErrors { errors } = error {
+        for ValidationError { message, locations } in errors {
+            let locations_and_source: Vec<_> = locations
+                .iter()
+                .map(|&location| {
+                    let source = source_for_location(&config.root_dir, location);
+                    (location, source)
+                })
+                .collect();
+            let mut error_message = format!("{}", message);
+            for (location, source) in locations_and_source {
+                if let Some(source) = source {
+                    write!(
+                        error_message,
+                        "\n{}",
+                        location.print(&source.text, source.line_index, source.column_index)
+                    )
+                    .unwrap();
+                } else {
+                    write!(error_message, "\n{:?}", location).unwrap();
                 }
-                Err(error)
+            }
+            error!("{}", error_message);
+        }
+    };
+}
+
+/// Emits one newline-delimited JSON record per source location, tagged with
+/// the project that produced it, to `diagnostic_sink` -- callers choose the
+/// stream (stdout by default, via `Compiler::new`; anything else via
+/// `Compiler::with_diagnostic_sink`) instead of it being hardcoded here.
+/// Locations whose source can't be loaded produce no record.
+fn print_project_error_json(
+    config: &Config,
+    diagnostic_sink: &Mutex<dyn std::io::Write + Send>,
+    project_name: Option<ProjectName>,
+    error: &BuildProjectError,
+) {
+    if let BuildProjectError::ValidationErrors { errors } = error {
+        let mut sink = diagnostic_sink.lock().unwrap();
+        for validation_error in errors {
+            if let Err(io_error) = diagnostics::write_validation_error_json(
+                &mut *sink,
+                &config.root_dir,
+                project_name,
+                validation_error,
+            ) {
+                error!("failed to write JSON diagnostic: {}", io_error);
             }
         }
     }
+}
 
-    fn print_project_error(&self, error: &BuildProjectError) {
-        if let BuildProjectError::ValidationErrors { errors } = error {
-            for ValidationError { message, locations } in errors {
-                let locations_and_source: Vec<_> = locations
-                    .iter()
-                    .map(|&location| {
-                        let source = source_for_location(&self.config.root_dir, location);
-                        (location, source)
-                    })
-                    .collect();
-                let mut error_message = format!("{}", message);
-                for (location, source) in locations_and_source {
-                    if let Some(source) = source {
-                        write!(
-                            error_message,
-                            "\n{}",
-                            location.print(&source.text, source.line_index, source.column_index)
-                        )
-                        .unwrap();
-                    } else {
-                        write!(error_message, "\n{:?}", location).unwrap();
+/// Bridges the LSP client's blocking `crossbeam_channel` receiver onto a
+/// cancel-safe `tokio` channel. `watch_with_lsp` polls `recv()` as one arm
+/// of a `select!` alongside the Watchman subscription, and a `select!` arm
+/// can be dropped mid-poll whenever the other arm wins -- `mpsc::Receiver::
+/// recv()` tolerates that (the message just sits in the channel for the
+/// next poll), but a fresh `spawn_blocking(|| receiver.recv())` per poll
+/// would not: the blocking task can't be aborted once started, so a dropped
+/// poll still silently consumes and discards one client message. Spawning
+/// the blocking loop once, up front, and forwarding through a channel
+/// sidesteps that entirely.
+struct ClientMessageReceiver {
+    messages: tokio::sync::mpsc::UnboundedReceiver<Option<ClientMessage>>,
+}
+
+impl ClientMessageReceiver {
+    fn spawn(connection: &Connection) -> Self {
+        let receiver = connection.receiver.clone();
+        let (tx, messages) = tokio::sync::mpsc::unbounded_channel();
+        task::spawn_blocking(move || loop {
+            match receiver.recv() {
+                Ok(message) => {
+                    if let Some(client_message) = lsp::parse_message(message) {
+                        if tx.send(Some(client_message)).is_err() {
+                            return;
+                        }
                     }
+                    // Not a message we care about (e.g. an unrelated
+                    // request); keep waiting for the next one.
+                }
+                Err(_) => {
+                    let _ = tx.send(None);
+                    return;
                 }
-                error!("{}", error_message);
             }
-        };
+        });
+        Self { messages }
+    }
+
+    async fn recv(&mut self) -> Result<Option<ClientMessage>> {
+        Ok(self.messages.recv().await.flatten())
     }
 }
 
+#[tracing::instrument(name = "build_projects", skip_all)]
 async fn build_projects<TPerfLogger: PerfLogger + 'static>(
     config: Arc<Config>,
     perf_logger: Arc<TPerfLogger>,
+    diagnostic_sink: Arc<Mutex<dyn std::io::Write + Send>>,
+    build_pool: Arc<rayon::ThreadPool>,
     setup_event: &impl PerfLogEvent,
     compiler_state: &CompilerState,
 ) -> Result<ArtifactMap> {
@@ -314,57 +580,110 @@ async fn build_projects<TPerfLogger: PerfLogger + 'static>(
         GraphQLAsts::from_graphql_sources_map(&compiler_state.graphql_sources)
     })?;
 
-    let build_results: Vec<_> = if let Some(only_project) = config.only_project {
+    let cancel = CancellationToken::new();
+    let total_projects = if config.only_project.is_some() {
+        1
+    } else {
+        config
+            .projects
+            .values()
+            .filter(|project_config| compiler_state.project_has_pending_changes(project_config.name))
+            .count()
+    };
+
+    let build_results: Vec<(ProjectName, std::result::Result<_, BuildProjectError>)> =
+        if let Some(only_project) = config.only_project {
         let project_config = config
             .projects
             .get(&only_project)
             .unwrap_or_else(|| panic!("Expected the project {} to exist", &only_project));
-        vec![build_project(
-            project_config,
-            compiler_state,
-            &graphql_asts,
-            Arc::clone(&perf_logger),
+        vec![(
+            only_project,
+            build_project(
+                project_config,
+                compiler_state,
+                &graphql_asts,
+                Arc::clone(&perf_logger),
+            ),
         )]
     } else {
-        config
-            .projects
-            .par_iter()
-            .filter_map(|(_name, project_config)| {
-                if compiler_state.project_has_pending_changes(project_config.name) {
-                    Some(build_project(
-                        project_config,
-                        compiler_state,
-                        &graphql_asts,
-                        Arc::clone(&perf_logger),
-                    ))
-                } else {
-                    None
-                }
-            })
-            .collect()
+        // Bound the build phase to its own token count (default: available
+        // parallelism), independent from the commit phase's pool below,
+        // rather than letting `par_iter` oversubscribe the global rayon
+        // pool on repos with many pending projects. `build_pool` is built
+        // once on `Compiler` and reused across every call, rather than
+        // paying thread-spawn/teardown cost on every incremental rebuild.
+        let cancel = &cancel;
+        build_pool.install(|| {
+            config
+                .projects
+                .par_iter()
+                .filter_map(|(_name, project_config)| {
+                    if config.fail_fast && cancel.is_cancelled() {
+                        return None;
+                    }
+                    if compiler_state.project_has_pending_changes(project_config.name) {
+                        let span = tracing::info_span!(
+                            "build_project",
+                            project_name = %project_config.name
+                        );
+                        let result = span.in_scope(|| {
+                            build_project(
+                                project_config,
+                                compiler_state,
+                                &graphql_asts,
+                                Arc::clone(&perf_logger),
+                            )
+                        });
+                        if config.fail_fast && result.is_err() {
+                            cancel.cancel();
+                        }
+                        Some((project_config.name, result))
+                    } else {
+                        None
+                    }
+                })
+                .collect()
+        })
     };
+    // Counts distinct projects that reached a final outcome, not phases: a
+    // project that fails in the build phase is done (never reaches commit),
+    // so it's counted here; a project that builds cleanly is only counted
+    // once it finishes the commit phase below, not again here.
+    let mut completed = 0usize;
     let mut results = Vec::new();
     let mut errors = Vec::new();
-    for result in build_results {
+    for (project_name, result) in build_results {
         match result {
             Ok(result) => results.push(result),
-            Err(error) => errors.push(error),
+            Err(error) => {
+                print_project_error(&config, &diagnostic_sink, Some(project_name), &error);
+                completed += 1;
+                errors.push(error);
+            }
         }
     }
 
     let errors = if errors.is_empty() {
-        let mut handles = Vec::new();
-        let errors_mutex = Arc::new(std::sync::Mutex::new(errors));
+        // `commit_project` is IO-bound (writing artifacts to disk), so give
+        // it its own token pool sized independently from the CPU-bound
+        // build phase above; a repo with hundreds of projects would
+        // otherwise spawn hundreds of commits at once and oversubscribe
+        // disk IO for no benefit.
+        let commit_pool = Arc::new(TokenPool::new(config.commit_tokens));
+        let scheduler = Scheduler::new(commit_pool);
         for result in results {
             let config = Arc::clone(&config);
-            let errors_mutex = Arc::clone(&errors_mutex);
             let perf_logger = Arc::clone(&perf_logger);
-            handles.push(task::spawn(async move {
+            let cancel = cancel.clone();
+            let diagnostic_sink = Arc::clone(&diagnostic_sink);
+            scheduler.push(async move {
                 let (project_name, schema, programs, artifacts) = result;
                 let project_config = config
                     .projects
                     .get(&project_name)
                     .unwrap_or_else(|| panic!("Expected the project {} to exist", project_name));
+                let span = tracing::info_span!("commit_project", project_name = %project_name);
                 let result = commit_project(
                     &config,
                     project_config,
@@ -373,22 +692,44 @@ async fn build_projects<TPerfLogger: PerfLogger + 'static>(
                     programs,
                     artifacts,
                 )
+                .instrument(span)
                 .await;
-                match result {
-                    Ok(_) => {}
-                    Err(error) => {
-                        let mut errors = errors_mutex.lock().unwrap();
-                        errors.push(error);
+                if let Err(error) = &result {
+                    print_project_error(&config, &diagnostic_sink, Some(project_name), error);
+                    if config.fail_fast {
+                        cancel.cancel();
                     }
                 }
-            }))
+                result
+            });
         }
-        futures::future::join_all(handles).await;
-        Arc::try_unwrap(errors_mutex).unwrap().into_inner().unwrap()
+        let commit_results = if config.fail_fast {
+            scheduler.run_until_cancelled(&cancel).await
+        } else {
+            scheduler.run().await
+        };
+        completed += commit_results.len();
+        commit_results
+            .into_iter()
+            .filter_map(|result| result.err())
+            .collect()
     } else {
         errors
     };
 
+    let status = if config.fail_fast && !errors.is_empty() && completed < total_projects {
+        BuildStatus::FailedFast {
+            completed,
+            total: total_projects,
+        }
+    } else {
+        BuildStatus::Completed {
+            project_count: total_projects,
+            error_count: errors.len(),
+        }
+    };
+    info!("[compiler] {}", status);
+
     if errors.is_empty() {
         let next_artifacts: ArtifactMap = Default::default();
         Ok(next_artifacts)
@@ -0,0 +1,185 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! A minimal Language Server front-end for the Relay compiler.
+//!
+//! This module only owns the LSP wire protocol: parsing incoming
+//! `lsp_server::Message`s into the handful of notifications/requests we
+//! care about, and turning `ValidationError` locations into `Diagnostic`
+//! ranges. The actual message loop lives on `Compiler::watch_with_lsp`,
+//! since it needs access to the same `CompilerState`/`FileSourceSubscription`
+//! the plain watch loop uses.
+
+use common::{Location, SourceLocationKey};
+use graphql_ir::ValidationError;
+use lsp_server::{Connection, Message, Notification, Request};
+use lsp_types::{
+    notification::Notification as _, request::Request as _, Diagnostic, DiagnosticSeverity,
+    DidChangeTextDocumentParams, DidOpenTextDocumentParams, InitializeParams, Position,
+    PublishDiagnosticsParams, Range, Url,
+};
+use std::collections::HashMap;
+
+use crate::diagnostics::source_span;
+use crate::errors::{Error, Result};
+use crate::watchman::source_for_location;
+
+/// Custom request editors can send to bypass the "no changes detected"
+/// short-circuit and force a full re-check of every project.
+pub enum ForceRebuild {}
+
+impl lsp_types::request::Request for ForceRebuild {
+    type Params = ();
+    type Result = ();
+    const METHOD: &'static str = "relay/forceRebuild";
+}
+
+/// An in-memory edit to a document that has not yet landed on disk.
+/// `CompilerState` has no API for applying an edit ahead of the matching
+/// on-disk change, so `watch_with_lsp` currently treats this the same as
+/// `ForceRebuild`: a re-check against whatever is already on disk, not a
+/// live-as-you-type check of `text` itself.
+pub struct DocumentEdit {
+    pub url: Url,
+    pub text: String,
+}
+
+/// The subset of incoming client messages the compiler's LSP loop reacts to.
+pub enum ClientMessage {
+    DocumentEdit(DocumentEdit),
+    ForceRebuild,
+    Shutdown,
+}
+
+/// Performs the `initialize`/`initialized` handshake, blocking until the
+/// client sends its `initialize` request. Malformed params from the client
+/// are reported as an `Error::LSPError` rather than crashing the server.
+pub fn initialize(connection: &Connection) -> Result<InitializeParams> {
+    let (id, params) = connection
+        .initialize_start()
+        .map_err(|source| Error::LSPError {
+            detail: source.to_string(),
+        })?;
+    let params: InitializeParams = serde_json::from_value(params).map_err(|err| Error::LSPError {
+        detail: format!("malformed initialize params: {}", err),
+    })?;
+    let server_capabilities = serde_json::json!({
+        "textDocumentSync": 1, // Full sync, so didChange carries the whole document text.
+    });
+    connection
+        .initialize_finish(id, serde_json::json!({ "capabilities": server_capabilities }))
+        .map_err(|source| Error::LSPError {
+            detail: source.to_string(),
+        })?;
+    Ok(params)
+}
+
+/// Translates a raw `lsp_server::Message` into the handful of notifications
+/// the compiler's watch loop understands, ignoring everything else.
+pub fn parse_message(message: Message) -> Option<ClientMessage> {
+    match message {
+        Message::Notification(Notification { method, params }) => match method.as_str() {
+            lsp_types::notification::DidOpenTextDocument::METHOD => {
+                let params: DidOpenTextDocumentParams = serde_json::from_value(params).ok()?;
+                Some(ClientMessage::DocumentEdit(DocumentEdit {
+                    url: params.text_document.uri,
+                    text: params.text_document.text,
+                }))
+            }
+            lsp_types::notification::DidChangeTextDocument::METHOD => {
+                let mut params: DidChangeTextDocumentParams =
+                    serde_json::from_value(params).ok()?;
+                // We declared full-document sync above, so the last change
+                // event always carries the complete new text.
+                let change = params.content_changes.pop()?;
+                Some(ClientMessage::DocumentEdit(DocumentEdit {
+                    url: params.text_document.uri,
+                    text: change.text,
+                }))
+            }
+            lsp_types::notification::Exit::METHOD => Some(ClientMessage::Shutdown),
+            _ => None,
+        },
+        Message::Request(request) => match request.method.as_str() {
+            ForceRebuild::METHOD => Some(ClientMessage::ForceRebuild),
+            _ => None,
+        },
+        Message::Response(_) => None,
+    }
+}
+
+/// Publishes one `publishDiagnostics` notification per entry in
+/// `diagnostics_by_file`. To clear diagnostics for a file that's since gone
+/// clean, the caller must include it here with an empty `Vec` — this
+/// function has no memory of what it published last time.
+pub fn publish_diagnostics(
+    connection: &Connection,
+    diagnostics_by_file: HashMap<Url, Vec<Diagnostic>>,
+) -> Result<(), crossbeam_channel::SendError<Message>> {
+    for (uri, diagnostics) in diagnostics_by_file {
+        let params = PublishDiagnosticsParams {
+            uri,
+            diagnostics,
+            version: None,
+        };
+        connection.sender.send(Message::Notification(Notification::new(
+            lsp_types::notification::PublishDiagnostics::METHOD.to_string(),
+            params,
+        )))?;
+    }
+    Ok(())
+}
+
+/// Converts a single `ValidationError` into one `Diagnostic` per source
+/// location, grouped by the file the location points into. Reuses the same
+/// `source_for_location`/`line_index`/`column_index` data the human-readable
+/// `print_project_error` formatter derives its output from. Locations whose
+/// source can't be loaded, or whose path can't be turned into a file URL,
+/// produce no `Diagnostic`.
+pub fn diagnostics_for_validation_error(
+    root_dir: &std::path::Path,
+    error: &ValidationError,
+    diagnostics_by_file: &mut HashMap<Url, Vec<Diagnostic>>,
+) {
+    for &location in &error.locations {
+        let source = match source_for_location(root_dir, location) {
+            Some(source) => source,
+            None => continue,
+        };
+        let uri = match file_path_to_url(location.source_location(), root_dir) {
+            Some(uri) => uri,
+            None => continue,
+        };
+        let span = source_span(location, &source);
+        diagnostics_by_file
+            .entry(uri)
+            .or_insert_with(Vec::new)
+            .push(Diagnostic {
+                range: Range::new(
+                    // LSP positions are zero-indexed; `source_span` mirrors
+                    // `Location::print`'s 1-indexed line/column.
+                    Position::new(
+                        (span.start_line as u32).saturating_sub(1),
+                        (span.start_column as u32).saturating_sub(1),
+                    ),
+                    Position::new(
+                        (span.end_line as u32).saturating_sub(1),
+                        (span.end_column as u32).saturating_sub(1),
+                    ),
+                ),
+                severity: Some(DiagnosticSeverity::Error),
+                source: Some("relay".to_string()),
+                message: error.message.to_string(),
+                ..Default::default()
+            });
+    }
+}
+
+fn file_path_to_url(source_location: SourceLocationKey, root_dir: &std::path::Path) -> Option<Url> {
+    let path = source_location.path()?;
+    Url::from_file_path(root_dir.join(path)).ok()
+}
@@ -0,0 +1,97 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Bridges the compiler's `PerfLogger` events onto `tracing` spans, so that
+//! in addition to the custom perf events consumed by `PerfLogger::flush()`,
+//! every phase also shows up as a navigable span in any `tracing` subscriber
+//! — including `console-subscriber`, for inspecting stalled tasks in
+//! `watch`/`watch_with_lsp` with `tokio-console`.
+//!
+//! This is an adapter, not a replacement: `TracingPerfLogger` wraps an inner
+//! `PerfLogger` and forwards every event to it unchanged, so switching it in
+//! doesn't regress whatever already consumes `PerfLogger` events.
+
+use common::{PerfLogEvent, PerfLogger};
+use std::time::Instant;
+use tracing::Span;
+
+/// Wraps `TInner` so that every perf event also opens a `tracing` span for
+/// its lifetime: a span named `perf_event`, carrying the event's name in its
+/// `name` field.
+pub struct TracingPerfLogger<TInner> {
+    inner: TInner,
+}
+
+impl<TInner: PerfLogger> TracingPerfLogger<TInner> {
+    pub fn new(inner: TInner) -> Self {
+        Self { inner }
+    }
+
+    /// Installs a `console-subscriber` on top of the default `tracing`
+    /// subscriber. Opt-in and meant to be called once, near the start of
+    /// `main`, only when a developer is actively attaching `tokio-console`
+    /// — it binds a gRPC server and adds per-task bookkeeping overhead that
+    /// isn't worth paying in a normal compiler run.
+    pub fn init_console_subscriber() {
+        console_subscriber::init();
+    }
+}
+
+impl<TInner: PerfLogger> PerfLogger for TracingPerfLogger<TInner> {
+    type PerfLogEvent = TracingPerfLogEvent<TInner::PerfLogEvent>;
+
+    fn create_event(&self, name: &'static str) -> Self::PerfLogEvent {
+        TracingPerfLogEvent {
+            span: tracing::info_span!("perf_event", name),
+            inner: self.inner.create_event(name),
+        }
+    }
+
+    fn complete_event(&self, event: Self::PerfLogEvent) {
+        self.inner.complete_event(event.inner);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// One perf event's `tracing::Span`, plus the inner event it forwards to.
+/// `start`/`stop`/`time` record both a tracing span entry (visible to
+/// `tokio-console` and any other subscriber) and the original perf-event
+/// timing (visible to `PerfLogger::flush()`), so nothing downstream of
+/// either has to change.
+pub struct TracingPerfLogEvent<TInnerEvent> {
+    span: Span,
+    inner: TInnerEvent,
+}
+
+impl<TInnerEvent: PerfLogEvent> PerfLogEvent for TracingPerfLogEvent<TInnerEvent> {
+    // A plain `Span`, not an `EnteredSpan`: timers are held across `.await`
+    // points (e.g. `incremental_check_time` in the watch loops), and an
+    // `EnteredSpan` is `!Send` and must never be held across an await — it
+    // would make those async fns `!Send` and misattribute the span across
+    // thread hops. `stop` re-enters the span just long enough to close it.
+    type Timer = (Span, Instant, TInnerEvent::Timer);
+
+    fn start(&self, name: &'static str) -> Self::Timer {
+        let span = tracing::info_span!(parent: &self.span, "phase", name);
+        (span, Instant::now(), self.inner.start(name))
+    }
+
+    fn stop(&self, timer: Self::Timer) {
+        let (span, start, inner_timer) = timer;
+        let _entered = span.enter();
+        tracing::trace!(elapsed_micros = start.elapsed().as_micros() as u64);
+        self.inner.stop(inner_timer);
+    }
+
+    fn time<T, F: FnOnce() -> T>(&self, name: &'static str, func: F) -> T {
+        let _span = tracing::info_span!(parent: &self.span, "phase", name).entered();
+        self.inner.time(name, func)
+    }
+}